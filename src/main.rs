@@ -1,10 +1,12 @@
 use serde::{Serialize, Deserialize};
 use std::env::args;
 
-use anyhow::*;
-use std::collections::HashMap;
+use anyhow::{ensure, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use rust_decimal::prelude::Zero;
+use rust_decimal::RoundingStrategy;
 
 // Use serde to parse entries,
 // Apply to mutable state
@@ -16,7 +18,100 @@ use rust_decimal::prelude::Zero;
 // in order to avoid rounding errors
 pub type Value = rust_decimal::Decimal;
 
-#[derive(Debug, Deserialize, PartialOrd, PartialEq)]
+/// Id of a client account. Kept distinct from `TxId` so the two can't be mixed up
+/// despite both being small integers on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ClientId(pub u16);
+
+/// Id of a deposit/withdrawal transaction, as referenced by later dispute/resolve/chargeback rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct TxId(pub u32);
+
+/// A monetary amount. Wraps `Value` so it can't be passed where a bare `Decimal` from an
+/// unrelated calculation was meant. A parsed amount keeps the scale it was written with
+/// until `normalized` rescales it to 4 decimal places.
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct TxAmount(pub Value);
+
+impl std::ops::Add for TxAmount {
+    type Output = TxAmount;
+    fn add(self, rhs: TxAmount) -> TxAmount {
+        TxAmount(self.0 + rhs.0)
+    }
+}
+
+impl std::ops::Sub for TxAmount {
+    type Output = TxAmount;
+    fn sub(self, rhs: TxAmount) -> TxAmount {
+        TxAmount(self.0 - rhs.0)
+    }
+}
+
+impl std::ops::AddAssign for TxAmount {
+    fn add_assign(&mut self, rhs: TxAmount) {
+        self.0 += rhs.0;
+    }
+}
+
+impl std::ops::SubAssign for TxAmount {
+    fn sub_assign(&mut self, rhs: TxAmount) {
+        self.0 -= rhs.0;
+    }
+}
+
+/// Monetary amounts are tracked with 4 digits of fractional precision, as per the
+/// expected CSV output format.
+const DECIMAL_PLACES: u32 = 4;
+
+impl TxAmount {
+    /// Rescale to exactly the ledger's 4 decimal places, using banker's rounding so
+    /// repeated rounding doesn't bias totals up or down.
+    ///
+    /// `round_dp_with_strategy` alone only rounds *down* to 4 places when there are
+    /// more than 4 already; it never pads a value that has fewer (e.g. `"2.0"` stays
+    /// at scale 1), so the explicit `rescale` below is required to get a consistent
+    /// 4-decimal-place output for every amount, not just ones that needed rounding.
+    pub fn normalized(self) -> TxAmount {
+        let mut v = self.0.round_dp_with_strategy(DECIMAL_PLACES, RoundingStrategy::MidpointNearestEven);
+        v.rescale(DECIMAL_PLACES);
+        TxAmount(v)
+    }
+}
+
+/// Errors raised while processing the input stream or loading/saving the ledger `State`.
+///
+/// `Csv`, `Io` and `Serde` are fatal: the input stream or the ledger store itself is
+/// broken and processing aborts. Every other variant is a business-rule rejection of one
+/// row and leaves the rest of the stream unaffected.
+#[derive(Debug, thiserror::Error)]
+pub enum LedgerError {
+    #[error("not enough available funds")]
+    NotEnoughFunds,
+    #[error("unknown tx {id:?} for client {client:?}")]
+    UnknownTx { client: ClientId, id: TxId },
+    #[error("tx {id:?} fell outside the dispute window and was evicted")]
+    TxExpired { id: TxId },
+    #[error("operation references a tx belonging to a different client")]
+    WrongClientForTx,
+    #[error("tx is already disputed")]
+    AlreadyDisputed,
+    #[error("tx is not currently disputed")]
+    NotDisputed,
+    #[error("entry is missing an amount")]
+    MissingAmount,
+    #[error("account is locked")]
+    FrozenAccount,
+    #[error("entry type {0:?} cannot be used here")]
+    InvalidEntry(EntryType),
+    #[error("malformed record: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to (de)serialize ledger state: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialOrd, PartialEq)]
 pub enum EntryType {
     #[serde(rename = "deposit")]
     Deposit,
@@ -39,7 +134,7 @@ impl EntryType {
     }
 }
 
-#[derive(Debug, PartialOrd, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialOrd, PartialEq)]
 pub enum EntryState {
     New,
     Processed,
@@ -63,10 +158,10 @@ impl Default for EntryState {
 pub struct Entry {
     #[serde(rename = "type")]
     typ: EntryType,
-    client: u16,
+    client: ClientId,
     #[serde(rename = "tx")]
-    id: u32,
-    amount: Option<Value>,
+    id: TxId,
+    amount: Option<TxAmount>,
 
     #[serde(skip_serializing, skip_deserializing)]
     state: EntryState,
@@ -74,35 +169,78 @@ pub struct Entry {
 
 #[derive(Debug, Default)]
 pub struct Account {
-    available: Value,
-    held: Value,
+    available: TxAmount,
+    held: TxAmount,
     locked: bool,
 }
 
 #[derive(Debug, Default)]
 pub struct State {
-    accounts: HashMap<u16, Account>,
-    // Replace with BTreeMap, and remove old transactions in order to keep memory low
-    // (limited dispute window)
-    transactions: HashMap<u32, Entry>,
+    accounts: HashMap<ClientId, Account>,
+    // BTreeMap so eviction can walk transactions oldest-tx-id-first.
+    transactions: BTreeMap<TxId, Entry>,
+    // How many processed transactions to retain for disputes; `None` keeps all of them
+    // forever, matching the old unbounded behavior.
+    dispute_window: Option<usize>,
+    // Ids that have actually been evicted, so a dispute against one of them can be
+    // reported as expired rather than unknown. Eviction can skip over a currently
+    // disputed tx and evict a higher id instead, so a single high-water mark isn't
+    // enough to tell "evicted" apart from "never submitted" for ids in the gap.
+    evicted: BTreeSet<TxId>,
 }
 
 impl State {
+    /// A `State` that keeps every transaction forever (the original behavior).
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A `State` that retains at most `window` transactions, evicting the lowest tx ids
+    /// first so disputes against very old transactions fail fast instead of pinning
+    /// memory for the lifetime of the stream. Currently disputed transactions are never
+    /// evicted, so more than `window` can be retained while disputes are open.
+    pub fn with_dispute_window(window: usize) -> Self {
+        State {
+            dispute_window: Some(window),
+            ..Default::default()
+        }
+    }
+
     /// Apply an entry from the input
-    pub fn apply(&mut self, tx: Entry) -> Result<()> {
+    pub fn apply(&mut self, tx: Entry) -> Result<(), LedgerError> {
         if tx.typ.is_tx() {
             self.apply_tx(tx)
         } else {
             self.apply_op(tx)
         }
     }
+
+    fn evict_expired(&mut self) {
+        let Some(window) = self.dispute_window else { return };
+        while self.transactions.len() > window {
+            let Some(&id) = self.transactions.iter()
+                .find(|(_, e)| e.state != EntryState::Disputed)
+                .map(|(id, _)| id)
+            else {
+                // Everything left over window is currently disputed; leave it be.
+                break;
+            };
+            self.transactions.remove(&id);
+            self.evicted.insert(id);
+        }
+    }
     // Apply a transaction to an account if possible, if not possible, which provides more information
-    pub fn apply_tx(&mut self, mut tx: Entry) -> Result<()> {
+    pub fn apply_tx(&mut self, mut tx: Entry) -> Result<(), LedgerError> {
         let acc = self.accounts
             .entry(tx.client)
             .or_insert_with(|| Default::default());
 
-        let amount = tx.amount.ok_or(Error::msg("Expected amount associated"))?;
+        if acc.locked {
+            return Err(LedgerError::FrozenAccount);
+        }
+
+        let amount = tx.amount.ok_or(LedgerError::MissingAmount)?.normalized();
+        tx.amount = Some(amount);
 
         match &tx.typ {
             EntryType::Deposit => {
@@ -113,82 +251,110 @@ impl State {
             }
             EntryType::Withdrawal => {
                 let res = acc.available - amount;
-                if res < Value::zero() {
+                if res.0 < Value::zero() {
                     tx.state = EntryState::Failed;
-                    bail!("Invalid withdrawal, not enough funds");
+                    return Err(LedgerError::NotEnoughFunds);
                 } else {
                     acc.available -= amount;
                     tx.state = EntryState::Processed
                 }
                 self.transactions.insert(tx.id, tx);
             }
-            _ => bail!("Invalid transaction: {:?}", tx)
+            typ => return Err(LedgerError::InvalidEntry(*typ)),
         }
+        self.evict_expired();
         Ok(())
     }
 
     // Apply an operation to pre-existing transaction
-    pub fn apply_op(&mut self, op: Entry) -> Result<()> {
-        let actual = self.transactions.get_mut(&op.id);
-        let actual = actual.ok_or_else(|| Error::msg("Tx not found"))?;
-        ensure!(actual.client == op.client, "Operation referencing tx of a different client");
+    pub fn apply_op(&mut self, op: Entry) -> Result<(), LedgerError> {
+        // Checked before anything tx-specific, same as `apply_tx`, so a locked account
+        // is always reported as `FrozenAccount` rather than some other rejection that
+        // happens to be checked first (e.g. a dispute against the wrong client).
+        if self.accounts.get(&op.client).is_some_and(|acc| acc.locked) {
+            return Err(LedgerError::FrozenAccount);
+        }
+        if self.evicted.contains(&op.id) {
+            return Err(LedgerError::TxExpired { id: op.id });
+        }
+
+        let actual = self.transactions.get_mut(&op.id)
+            .ok_or(LedgerError::UnknownTx { client: op.client, id: op.id })?;
+        if actual.client != op.client {
+            return Err(LedgerError::WrongClientForTx);
+        }
 
         let acc = self.accounts.get_mut(&op.client)
-            .ok_or_else(|| Error::msg("Client missing"))?;
-        let amount = actual.amount.ok_or_else(|| Error::msg("Missing amount"))?;
+            .ok_or(LedgerError::UnknownTx { client: op.client, id: op.id })?;
+        let amount = actual.amount.ok_or(LedgerError::MissingAmount)?;
 
         match &op.typ {
             // Allowing disputes of both deposits and withdrawals for now, spec requires us to lock funds
             // This seems weird from my position, but let's follow the spec and see from there
             EntryType::Dispute => {
-                ensure!(actual.typ.is_tx(), "Attempting to dispute {:?}", actual.typ);
+                if !actual.typ.is_tx() {
+                    return Err(LedgerError::InvalidEntry(actual.typ));
+                }
                 // Dispute -> resolve -> dispute flow sounds possible, let's allow it
-                ensure!(actual.state == EntryState::Processed || actual.state == EntryState::Resolved,
-                    "Attempting to dispute tx in state: {:?}", actual.state);
+                if !(actual.state == EntryState::Processed || actual.state == EntryState::Resolved) {
+                    return Err(LedgerError::AlreadyDisputed);
+                }
 
                 actual.state = EntryState::Disputed;
                 acc.held += amount;
                 acc.available -= amount;
             }
             EntryType::Resolve => {
-                ensure!(acc.held >= amount, "Client held funds missing");
-                ensure!(actual.state == EntryState::Disputed, "Only disputed transactions can be resolved");
+                if actual.state != EntryState::Disputed {
+                    return Err(LedgerError::NotDisputed);
+                }
+                if acc.held < amount {
+                    return Err(LedgerError::NotEnoughFunds);
+                }
 
                 actual.state = EntryState::Resolved;
                 acc.held -= amount;
                 acc.available += amount;
             }
             EntryType::Chargeback => {
-                ensure!(acc.held >= amount, "Client held funds missing");
-                ensure!(actual.state == EntryState::Disputed, "Only disputed transactions can be charged back");
+                if actual.state != EntryState::Disputed {
+                    return Err(LedgerError::NotDisputed);
+                }
+                if acc.held < amount {
+                    return Err(LedgerError::NotEnoughFunds);
+                }
 
                 actual.state = EntryState::Chargeback;
                 acc.held -= amount;
                 acc.locked = true;
             }
-            _ => bail!("Invalid operation {:?}", op),
+            typ => return Err(LedgerError::InvalidEntry(*typ)),
         }
         Ok(())
     }
 
 
-    pub fn write(&self, w: impl Write) -> Result<()> {
+    pub fn write(&self, w: impl Write) -> Result<(), LedgerError> {
         // Different fields than our inner account repr, create local temp struct for output
         #[derive(Serialize)]
         struct AccountOut {
-            client: u16,
-            available: Value,
-            held: Value,
-            total: Value,
+            client: ClientId,
+            available: TxAmount,
+            held: TxAmount,
+            total: TxAmount,
             locked: bool,
         }
+        // Collect into a BTreeMap first so output is always ascending by client id,
+        // rather than whatever order the HashMap happens to iterate in.
+        let ordered: std::collections::BTreeMap<_, _> = self.accounts.iter().collect();
+
         let mut writer = csv::Writer::from_writer(w);
-        for (id, acc) in &self.accounts {
+        for (id, acc) in ordered {
             writer.serialize(&AccountOut {
                 client: *id,
-                available: acc.available,
-                held: acc.held,
-                total: acc.available + acc.held,
+                available: acc.available.normalized(),
+                held: acc.held.normalized(),
+                total: (acc.available + acc.held).normalized(),
                 locked: acc.locked,
             })?;
         }
@@ -196,33 +362,447 @@ impl State {
     }
 }
 
-pub fn process_stream(r: impl Read) -> Result<State> {
-    let mut state = State::default();
-
-    let mut rdr = csv::ReaderBuilder::new()
+fn csv_reader(r: impl Read) -> csv::Reader<impl Read> {
+    csv::ReaderBuilder::new()
         .has_headers(true)
         .flexible(true)
         .trim(csv::Trim::All)
         .comment(Some(b'#'))
         .terminator(csv::Terminator::CRLF)
-        .from_reader(r);
+        .from_reader(r)
+}
+
+/// Apply every `Entry` in `r` to an existing `state`, strictly in arrival order.
+///
+/// Malformed records or IO failures from the CSV reader are fatal and abort the whole
+/// stream (`Err`); business-rule rejections (insufficient funds, unknown tx, ...) are
+/// collected per-row and returned to the caller so it can inspect exactly which rows
+/// failed and why. Shared by `process_stream` (fresh state) and `main` (state reloaded
+/// from a `LedgerStore`, so a previous run can be resumed).
+pub fn apply_stream(state: &mut State, r: impl Read) -> Result<Vec<LedgerError>, LedgerError> {
+    let mut errors = Vec::new();
+    let mut rdr = csv_reader(r);
 
     for tx in rdr.deserialize() {
-        let tx: Entry = tx?;
+        let tx: Entry = tx.map_err(LedgerError::Csv)?;
         if let Err(e) = state.apply(tx) {
-            eprintln!("Error: {}", e);
+            errors.push(e);
+        }
+    }
+
+    Ok(errors)
+}
+
+/// Run every `Entry` in `r` against a fresh `State`, strictly in arrival order.
+///
+/// `dispute_window` bounds how many transactions are kept around for future disputes
+/// (see `State::with_dispute_window`); pass `None` to keep them all, trading memory for
+/// an unlimited dispute horizon. Kept around alongside `process_stream_parallel` since a
+/// single `State` processed in order is easiest to reason about when debugging.
+pub fn process_stream(r: impl Read, dispute_window: Option<usize>) -> Result<(State, Vec<LedgerError>), LedgerError> {
+    let mut state = dispute_window.map_or_else(State::new, State::with_dispute_window);
+    let errors = apply_stream(&mut state, r)?;
+    Ok((state, errors))
+}
+
+/// Like `process_stream`, but fans entries out to `workers` threads hashed by `client`.
+///
+/// Each worker owns a disjoint shard of accounts/transactions and applies its clients'
+/// entries strictly in arrival order, so a dispute always sees the deposit that came
+/// before it on the same client. The shards are merged into a single `State` once every
+/// row has been dispatched.
+///
+/// When `dispute_window` is `None`, the merged result matches what `process_stream`
+/// would have produced serially. When it's `Some(n)`, it does *not*: each worker seeds
+/// its own `State::with_dispute_window(n)` and evicts independently, so retention is
+/// `n` transactions per shard rather than `n` total across the merged state.
+pub fn process_stream_parallel(r: impl Read, workers: usize, dispute_window: Option<usize>) -> Result<(State, Vec<LedgerError>), LedgerError> {
+    let workers = workers.max(1);
+
+    let mut senders = Vec::with_capacity(workers);
+    let mut handles = Vec::with_capacity(workers);
+    for _ in 0..workers {
+        let (tx, rx) = std::sync::mpsc::channel::<Entry>();
+        senders.push(tx);
+        handles.push(std::thread::spawn(move || {
+            let mut shard = dispute_window.map_or_else(State::new, State::with_dispute_window);
+            let mut errors = Vec::new();
+            for entry in rx {
+                if let Err(e) = shard.apply(entry) {
+                    errors.push(e);
+                }
+            }
+            (shard, errors)
+        }));
+    }
+
+    let mut rdr = csv_reader(r);
+    for tx in rdr.deserialize() {
+        let tx: Entry = tx.map_err(LedgerError::Csv)?;
+        let shard = tx.client.0 as usize % workers;
+        // Workers only ever exit once we drop their sender below, so this can't fail.
+        senders[shard].send(tx).ok();
+    }
+    drop(senders);
+
+    let mut state = State::default();
+    let mut errors = Vec::new();
+    for handle in handles {
+        let (shard, shard_errors) = handle.join().expect("ledger worker thread panicked");
+        state.accounts.extend(shard.accounts);
+        state.transactions.extend(shard.transactions);
+        errors.extend(shard_errors);
+    }
+
+    Ok((state, errors))
+}
+
+// `State` itself isn't `Serialize`/`Deserialize` (`Account` has no serde derives, and
+// `Entry` only deserializes the CSV input and skips its `state`), so persistence goes
+// through these plain mirror structs instead, the same way `write` goes through `AccountOut`.
+#[derive(Serialize, Deserialize)]
+struct PersistedEntry {
+    typ: EntryType,
+    client: ClientId,
+    id: TxId,
+    amount: Option<TxAmount>,
+    state: EntryState,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedAccount {
+    available: TxAmount,
+    held: TxAmount,
+    locked: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct PersistedState {
+    accounts: HashMap<ClientId, PersistedAccount>,
+    transactions: BTreeMap<TxId, PersistedEntry>,
+    dispute_window: Option<usize>,
+    evicted: BTreeSet<TxId>,
+}
+
+impl From<&State> for PersistedState {
+    fn from(state: &State) -> Self {
+        PersistedState {
+            accounts: state.accounts.iter()
+                .map(|(id, acc)| (*id, PersistedAccount { available: acc.available, held: acc.held, locked: acc.locked }))
+                .collect(),
+            transactions: state.transactions.iter()
+                .map(|(id, tx)| (*id, PersistedEntry { typ: tx.typ, client: tx.client, id: tx.id, amount: tx.amount, state: tx.state }))
+                .collect(),
+            dispute_window: state.dispute_window,
+            evicted: state.evicted.clone(),
         }
     }
+}
 
-    Ok(state)
+impl From<PersistedState> for State {
+    fn from(persisted: PersistedState) -> Self {
+        State {
+            accounts: persisted.accounts.into_iter()
+                .map(|(id, acc)| (id, Account { available: acc.available, held: acc.held, locked: acc.locked }))
+                .collect(),
+            transactions: persisted.transactions.into_iter()
+                .map(|(id, tx)| (id, Entry { typ: tx.typ, client: tx.client, id: tx.id, amount: tx.amount, state: tx.state }))
+                .collect(),
+            dispute_window: persisted.dispute_window,
+            evicted: persisted.evicted,
+        }
+    }
+}
+
+/// Pluggable persistence for the ledger, so a long-running or interrupted stream can
+/// resume from where a previous run left off instead of starting from an empty `State`.
+pub trait LedgerStore {
+    /// Load the last saved `State`, or an empty one if nothing has been saved yet.
+    fn load(&self) -> Result<State, LedgerError>;
+    /// Persist `state` so a future `load()` (including after a crash) sees it.
+    fn save(&self, state: &State) -> Result<(), LedgerError>;
+}
+
+/// A `LedgerStore` backed by a single JSON file on disk.
+pub struct FileLedgerStore {
+    path: PathBuf,
+}
+
+impl FileLedgerStore {
+    /// Open (or prepare to create) a store at `path`. Nothing touches disk until
+    /// `load`/`save` is called.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileLedgerStore { path: path.into() }
+    }
 }
 
+impl LedgerStore for FileLedgerStore {
+    fn load(&self) -> Result<State, LedgerError> {
+        if !self.path.exists() {
+            return Ok(State::new());
+        }
+        let bytes = std::fs::read(&self.path)?;
+        let persisted: PersistedState = serde_json::from_slice(&bytes)?;
+        Ok(persisted.into())
+    }
+
+    fn save(&self, state: &State) -> Result<(), LedgerError> {
+        let persisted = PersistedState::from(state);
+        let bytes = serde_json::to_vec_pretty(&persisted)?;
+
+        // Write to a sibling temp file and rename over the real path, so a crash
+        // mid-write can't leave a half-written, unreadable store behind.
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, &bytes)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
 
 fn main() -> Result<()> {
     let args: Vec<_> = args().collect();
     ensure!(args.len() > 1, "Missing input file argument");
     let ifile = std::fs::File::open(&args[1])?;
-    let state = process_stream(ifile)?;
+
+    // An optional second argument opens (or creates) a persistent store: the ledger
+    // resumes from whatever was saved there and the merged result is written back.
+    // An empty second argument means no store.
+    let store = args.get(2)
+        .filter(|path| !path.is_empty())
+        .map(|path| FileLedgerStore::new(Path::new(path)));
+
+    // An optional third argument selects the per-client sharded parallel path instead
+    // of the sequential one, naming how many worker threads to shard across. Only
+    // applies when there's no store to resume from, since a resumed `State` isn't
+    // something `process_stream_parallel` knows how to seed its shards from.
+    let workers: Option<usize> = args.get(3).and_then(|n| n.parse().ok());
+
+    let state = match (&store, workers) {
+        (Some(store), _) => {
+            let mut state = store.load()?;
+            apply_stream(&mut state, ifile)?;
+            state
+        }
+        (None, Some(workers)) if workers > 1 => process_stream_parallel(ifile, workers, None)?.0,
+        (None, _) => process_stream(ifile, None)?.0,
+    };
+
     state.write(std::io::stdout())?;
+
+    if let Some(store) = &store {
+        store.save(&state)?;
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(csv: &str) -> String {
+        let (state, errors) = process_stream(csv.as_bytes(), None).unwrap();
+        assert!(errors.is_empty(), "unexpected row errors: {:?}", errors);
+        let mut out = Vec::new();
+        state.write(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn output_is_sorted_by_client_id() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,3,1,1.0\n\
+                   deposit,1,2,2.0\n\
+                   deposit,2,3,3.0\n";
+        assert_eq!(
+            run(csv),
+            "client,available,held,total,locked\n\
+             1,2.0000,0.0000,2.0000,false\n\
+             2,3.0000,0.0000,3.0000,false\n\
+             3,1.0000,0.0000,1.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn dispute_and_chargeback_lock_funds() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,5.0\n\
+                   dispute,1,1,\n\
+                   chargeback,1,1,\n";
+        assert_eq!(
+            run(csv),
+            "client,available,held,total,locked\n\
+             1,0.0000,0.0000,0.0000,true\n"
+        );
+    }
+
+    #[test]
+    fn locked_account_rejects_further_ops_and_keeps_balances() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,5.0\n\
+                   dispute,1,1,\n\
+                   chargeback,1,1,\n\
+                   deposit,1,2,1.0\n\
+                   withdrawal,1,3,1.0\n\
+                   dispute,1,1,\n";
+        let (state, errors) = process_stream(csv.as_bytes(), None).unwrap();
+        assert!(matches!(
+            errors.as_slice(),
+            [LedgerError::FrozenAccount, LedgerError::FrozenAccount, LedgerError::FrozenAccount]
+        ));
+
+        let mut out = Vec::new();
+        state.write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n\
+             1,0.0000,0.0000,0.0000,true\n"
+        );
+    }
+
+    #[test]
+    fn locked_account_rejects_wrong_client_dispute_as_frozen_not_wrong_client() {
+        // client 1 is locked via chargeback; a dispute against tx 4 (which actually
+        // belongs to client 2) should still report FrozenAccount first, since the
+        // account-level lock is checked before any tx-specific details.
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,5.0\n\
+                   dispute,1,1,\n\
+                   chargeback,1,1,\n\
+                   deposit,2,4,1.0\n\
+                   dispute,1,4,\n";
+        let (_state, errors) = process_stream(csv.as_bytes(), None).unwrap();
+        assert!(matches!(errors.as_slice(), [LedgerError::FrozenAccount]));
+    }
+
+    #[test]
+    fn whole_number_amounts_are_still_padded_to_four_decimal_places() {
+        // round_dp_with_strategy alone doesn't pad scale up, so a value like "2.0"
+        // (scale 1) would otherwise serialize as "2.0" instead of "2.0000".
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,2.0\n";
+        assert_eq!(
+            run(csv),
+            "client,available,held,total,locked\n\
+             1,2.0000,0.0000,2.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn amounts_are_rounded_to_four_decimal_places() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10.0\n\
+                   withdrawal,1,2,3.12345\n";
+        assert_eq!(
+            run(csv),
+            "client,available,held,total,locked\n\
+             1,6.8766,0.0000,6.8766,false\n"
+        );
+    }
+
+    #[test]
+    fn dispute_window_evicts_old_transactions() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,1.0\n\
+                   deposit,1,2,2.0\n\
+                   dispute,1,1,\n";
+        let (_state, errors) = process_stream(csv.as_bytes(), Some(1)).unwrap();
+        assert!(matches!(errors.as_slice(), [LedgerError::TxExpired { id: TxId(1) }]));
+    }
+
+    #[test]
+    fn dispute_window_does_not_misreport_never_submitted_tx_as_expired() {
+        // window=1: tx1 is kept alive by its dispute, so tx3 gets evicted in its place,
+        // leaving a gap at tx2 that was never submitted at all.
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,1.0\n\
+                   dispute,1,1,\n\
+                   deposit,1,3,3.0\n\
+                   dispute,1,2,\n";
+        let (_state, errors) = process_stream(csv.as_bytes(), Some(1)).unwrap();
+        assert!(matches!(
+            errors.as_slice(),
+            [LedgerError::UnknownTx { id: TxId(2), .. }]
+        ));
+    }
+
+    #[test]
+    fn file_ledger_store_round_trips_full_state() {
+        // Exercise a locked account, an evicted tx and a still-disputed tx, so the
+        // round trip through PersistedState actually covers every field that matters.
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,1.0\n\
+                   deposit,1,2,2.0\n\
+                   dispute,1,2,\n\
+                   deposit,2,3,5.0\n\
+                   dispute,2,3,\n\
+                   chargeback,2,3,\n\
+                   deposit,1,4,4.0\n";
+        let (state, errors) = process_stream(csv.as_bytes(), Some(2)).unwrap();
+        assert!(errors.is_empty(), "unexpected row errors: {:?}", errors);
+
+        let path = std::env::temp_dir().join(format!("tproc-round-trip-test-{}.json", std::process::id()));
+        let store = FileLedgerStore::new(&path);
+        store.save(&state).unwrap();
+        let reloaded = store.load().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut original_out = Vec::new();
+        state.write(&mut original_out).unwrap();
+        let mut reloaded_out = Vec::new();
+        reloaded.write(&mut reloaded_out).unwrap();
+        assert_eq!(original_out, reloaded_out);
+
+        assert_eq!(state.evicted, reloaded.evicted);
+        assert_eq!(state.dispute_window, reloaded.dispute_window);
+        assert_eq!(state.transactions.len(), reloaded.transactions.len());
+
+        // tx 2 is still disputed after reload, so resolving it should still work -
+        // proving EntryState::Disputed survived the JSON round trip.
+        let mut reloaded = reloaded;
+        reloaded.apply(Entry { typ: EntryType::Resolve, client: ClientId(1), id: TxId(2), amount: None, state: EntryState::New }).unwrap();
+    }
+
+    #[test]
+    fn parallel_dispute_window_is_per_shard_not_global() {
+        // Two clients, each depositing twice, hashed to separate shards with `workers:
+        // 2`. With window=2 sequential retains 2 transactions total; parallel retains
+        // up to 2 per shard (4 total here), since each shard's window is independent.
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,1.0\n\
+                   deposit,2,2,2.0\n\
+                   deposit,1,3,3.0\n\
+                   deposit,2,4,4.0\n";
+
+        let (sequential, _) = process_stream(csv.as_bytes(), Some(2)).unwrap();
+        let (parallel, _) = process_stream_parallel(csv.as_bytes(), 2, Some(2)).unwrap();
+
+        assert_eq!(sequential.transactions.len(), 2);
+        assert_eq!(parallel.transactions.len(), 4);
+    }
+
+    #[test]
+    fn parallel_processing_matches_sequential() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,1.0\n\
+                   deposit,2,2,2.0\n\
+                   deposit,3,3,3.0\n\
+                   withdrawal,1,4,0.5\n\
+                   dispute,2,2,\n\
+                   resolve,2,2,\n\
+                   deposit,3,5,1.0\n\
+                   dispute,3,5,\n\
+                   chargeback,3,5,\n";
+
+        let (sequential, seq_errors) = process_stream(csv.as_bytes(), None).unwrap();
+        let (parallel, par_errors) = process_stream_parallel(csv.as_bytes(), 4, None).unwrap();
+
+        let mut seq_out = Vec::new();
+        sequential.write(&mut seq_out).unwrap();
+        let mut par_out = Vec::new();
+        parallel.write(&mut par_out).unwrap();
+
+        assert_eq!(seq_errors.len(), par_errors.len());
+        assert_eq!(String::from_utf8(seq_out).unwrap(), String::from_utf8(par_out).unwrap());
+    }
+}